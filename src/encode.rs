@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: GPL-2.0-only
+/*
+ * Copyright (C) 2021 Carles Pey <cpey@pm.me>
+ */
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset};
+use std::fmt;
+use std::io::Write;
+
+use crate::priority::{self, Priority};
+use crate::TIME_FORMAT;
+
+/// Which captured stream a `LogLine` came from.
+#[derive(Clone, Copy, Debug)]
+pub enum Source {
+    Syslog,
+    Stdin,
+    Input(usize),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Syslog => write!(f, "syslog"),
+            Source::Stdin => write!(f, "stdin"),
+            Source::Input(i) => write!(f, "input{}", i),
+        }
+    }
+}
+
+/// A single, already-timestamped log entry tagged with its origin and,
+/// when known, its syslog/kernel severity.
+pub struct LogLine {
+    pub timestamp: DateTime<FixedOffset>,
+    pub source: Source,
+    pub message: String,
+    pub priority: Option<Priority>,
+}
+
+/// Serializes `LogLine`s to a writer in a particular output format.
+pub trait Encoder {
+    fn write_line(&mut self, w: &mut dyn Write, line: &LogLine) -> Result<()>;
+}
+
+/// The original `<timestamp> <message>` plain-text format, used for
+/// `--format text` (the default). Carries no priority tag, so existing
+/// scripts parsing dmerg's default output see no change in shape.
+pub struct TextEncoder;
+
+impl Encoder for TextEncoder {
+    fn write_line(&mut self, w: &mut dyn Write, line: &LogLine) -> Result<()> {
+        writeln!(w, "{} {}", line.timestamp.format(TIME_FORMAT), line.message)?;
+        Ok(())
+    }
+}
+
+/// Internal `<timestamp> <priority-tag> <message>` format our own
+/// syslog/stdin capture files are written in, so the priority survives
+/// the later merge pass even when the final `--format` is something
+/// else. Never selected via `--format`.
+pub struct CaptureEncoder;
+
+impl Encoder for CaptureEncoder {
+    fn write_line(&mut self, w: &mut dyn Write, line: &LogLine) -> Result<()> {
+        writeln!(
+            w,
+            "{} {} {}",
+            line.timestamp.format(TIME_FORMAT),
+            priority::tag(line.priority),
+            line.message
+        )?;
+        Ok(())
+    }
+}
+
+/// One JSON object per line (NDJSON), for downstream tooling.
+pub struct JsonEncoder;
+
+impl Encoder for JsonEncoder {
+    fn write_line(&mut self, w: &mut dyn Write, line: &LogLine) -> Result<()> {
+        let priority = match line.priority {
+            Some(p) => p.to_string(),
+            None => "unknown".to_string(),
+        };
+        writeln!(
+            w,
+            "{{\"ts\":\"{}\",\"source\":\"{}\",\"priority\":\"{}\",\"msg\":{}}}",
+            line.timestamp.format(TIME_FORMAT),
+            line.source,
+            priority,
+            escape_json(&line.message)
+        )?;
+        Ok(())
+    }
+}
+
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Builds the `Encoder` selected via `--format`.
+pub fn encoder_for(format: &str) -> Result<Box<dyn Encoder>> {
+    match format {
+        "text" => Ok(Box::new(TextEncoder)),
+        "json" => Ok(Box::new(JsonEncoder)),
+        other => Err(anyhow!("Unknown output format: {}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_json_escapes_control_and_special_chars() {
+        assert_eq!(escape_json("plain"), "\"plain\"");
+        assert_eq!(escape_json("a\"b"), "\"a\\\"b\"");
+        assert_eq!(escape_json("a\\b"), "\"a\\\\b\"");
+        assert_eq!(escape_json("a\nb\tb"), "\"a\\nb\\tb\"");
+        assert_eq!(escape_json("a\u{1}b"), "\"a\\u0001b\"");
+    }
+
+    #[test]
+    fn encoder_for_rejects_unknown_format() {
+        assert!(encoder_for("text").is_ok());
+        assert!(encoder_for("json").is_ok());
+        assert!(encoder_for("xml").is_err());
+    }
+
+    fn line(priority: Option<Priority>) -> LogLine {
+        LogLine {
+            timestamp: DateTime::parse_from_rfc3339("2021-01-01T00:00:00+00:00").unwrap(),
+            source: Source::Stdin,
+            message: "hello world".to_string(),
+            priority,
+        }
+    }
+
+    #[test]
+    fn text_encoder_carries_no_priority_tag() {
+        let mut buf = Vec::new();
+        TextEncoder.write_line(&mut buf, &line(Some(Priority::Err))).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "2021-01-01T00:00:00.000000+0000 hello world\n"
+        );
+    }
+
+    #[test]
+    fn capture_encoder_tags_priority_for_the_later_merge() {
+        let mut buf = Vec::new();
+        CaptureEncoder.write_line(&mut buf, &line(Some(Priority::Err))).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "2021-01-01T00:00:00.000000+0000 <3> hello world\n"
+        );
+    }
+}
@@ -0,0 +1,185 @@
+// SPDX-License-Identifier: GPL-2.0-only
+/*
+ * Copyright (C) 2021 Carles Pey <cpey@pm.me>
+ */
+
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use chrono::FixedOffset;
+use regex::Regex;
+
+use crate::priority::Priority;
+
+/// Regex, time-window and priority predicate applied uniformly to live
+/// console echo, the per-source capture files, and the final merged
+/// stream, so all three agree on which lines survive.
+pub struct LineFilter {
+    grep: Option<Regex>,
+    grep_invert: Option<Regex>,
+    since: Option<DateTime<FixedOffset>>,
+    until: Option<DateTime<FixedOffset>>,
+    min_priority: Option<Priority>,
+    min_time: Option<DateTime<FixedOffset>>,
+}
+
+impl LineFilter {
+    pub fn new(
+        grep: &Option<String>,
+        grep_invert: &Option<String>,
+        since: &Option<String>,
+        until: &Option<String>,
+        min_priority: &Option<String>,
+        min_time: Option<DateTime<FixedOffset>>,
+    ) -> Result<Self> {
+        let grep = grep
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .context("Invalid --grep pattern")?;
+        let grep_invert = grep_invert
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .context("Invalid --grep-invert pattern")?;
+        let since = since
+            .as_deref()
+            .map(DateTime::parse_from_rfc3339)
+            .transpose()
+            .context("Invalid --since timestamp")?;
+        let until = until
+            .as_deref()
+            .map(DateTime::parse_from_rfc3339)
+            .transpose()
+            .context("Invalid --until timestamp")?;
+        let min_priority = min_priority
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .context("Invalid --min-priority level")?;
+
+        Ok(LineFilter {
+            grep,
+            grep_invert,
+            since,
+            until,
+            min_priority,
+            min_time,
+        })
+    }
+
+    /// Whether a line at `timestamp` with the given `message` and (if
+    /// known) `priority` should be kept. A line with unknown priority
+    /// always passes `--min-priority`, since there's nothing to compare.
+    ///
+    /// `live` scopes `min_time` (the "skip the ring buffer" cutoff from
+    /// process start) to dmerg's own syslog/stdin capture; an already
+    /// timestamped `--input` file is never gated by it, or a merge of an
+    /// old capture would come back empty by default.
+    pub fn keep(
+        &self,
+        timestamp: DateTime<FixedOffset>,
+        message: &str,
+        priority: Option<Priority>,
+        live: bool,
+    ) -> bool {
+        if live {
+            if let Some(min_time) = self.min_time {
+                if timestamp < min_time {
+                    return false;
+                }
+            }
+        }
+        if let Some(since) = self.since {
+            if timestamp < since {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if timestamp > until {
+                return false;
+            }
+        }
+        if let Some(re) = &self.grep {
+            if !re.is_match(message) {
+                return false;
+            }
+        }
+        if let Some(re) = &self.grep_invert {
+            if re.is_match(message) {
+                return false;
+            }
+        }
+        if let (Some(min), Some(p)) = (self.min_priority, priority) {
+            if p > min {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(s: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(s).unwrap()
+    }
+
+    fn filter(min_time: Option<&str>, min_priority: Option<&str>) -> LineFilter {
+        LineFilter::new(
+            &None,
+            &None,
+            &None,
+            &None,
+            &min_priority.map(String::from),
+            min_time.map(t),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn min_time_drops_lines_before_it_only_for_live_sources() {
+        let f = filter(Some("2021-01-01T00:00:00+00:00"), None);
+        assert!(!f.keep(t("2020-12-31T23:59:59+00:00"), "msg", None, true));
+        assert!(f.keep(t("2021-01-01T00:00:00+00:00"), "msg", None, true));
+        // An --input file predates dmerg starting up; min_time must not
+        // gate it or merging an old capture would come back empty.
+        assert!(f.keep(t("2020-12-31T23:59:59+00:00"), "msg", None, false));
+    }
+
+    #[test]
+    fn min_priority_always_keeps_unknown_priority() {
+        let f = filter(None, Some("err"));
+        assert!(f.keep(t("2021-01-01T00:00:00+00:00"), "msg", None, true));
+        assert!(!f.keep(
+            t("2021-01-01T00:00:00+00:00"),
+            "msg",
+            Some(Priority::Debug),
+            true
+        ));
+        assert!(f.keep(
+            t("2021-01-01T00:00:00+00:00"),
+            "msg",
+            Some(Priority::Emerg),
+            true
+        ));
+    }
+
+    #[test]
+    fn grep_and_grep_invert_compose() {
+        let f = LineFilter::new(
+            &Some("foo".to_string()),
+            &Some("bar".to_string()),
+            &None,
+            &None,
+            &None,
+            None,
+        )
+        .unwrap();
+        let now = t("2021-01-01T00:00:00+00:00");
+        assert!(f.keep(now, "foo line", None, true));
+        assert!(!f.keep(now, "foo bar line", None, true));
+        assert!(!f.keep(now, "other line", None, true));
+    }
+}
@@ -4,22 +4,36 @@
  */
 
 use anyhow::{anyhow, Context, Result};
-use chrono::{DateTime, FixedOffset, Local};
+use chrono::{DateTime, FixedOffset, Local, TimeZone, Utc};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use regex::Regex;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::fs::{self, File};
 use std::io::{self, prelude::*, Write};
 use std::process::{ChildStdout, Command, Stdio};
 use std::str;
 use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
 use std::thread;
 use structopt::StructOpt;
 
+mod encode;
+mod filter;
+mod net;
+mod priority;
+mod stats;
+use encode::{Encoder, LogLine, Source};
+use filter::LineFilter;
+use net::NetSink;
+use priority::Priority;
+use stats::Stats;
+
 const SYSLOG_FNAME: &str = "/tmp/dmerg.syslog";
 const STDIN_FNAME: &str = "/tmp/dmerg.stdin";
 const FUSED_FNAME: &str = "dmerged";
-const UNIXTIME: &str = "1970-01-01T00:00:00.000000+00:00";
-const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f%z";
+pub(crate) const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f%z";
 
 fn ctrl_channel() -> Result<(Receiver<()>, Receiver<()>)> {
     let (sender1, receiver1) = channel();
@@ -58,24 +72,83 @@ fn get_syslog_line(reader: io::BufReader<ChildStdout>) -> Result<Receiver<String
     Ok(rx)
 }
 
+// dmesg's `-x`/`--decode` prefix, e.g. "kern  :warn  : <iso-time> message".
+// Captures the human-readable level name so we can recover a `Priority`
+// without the raw `<N>` prefix, which `--time-format iso` suppresses.
+fn strip_dmesg_decode_prefix(line: &str, re: &Regex) -> (Option<Priority>, String) {
+    match re.captures(line) {
+        Some(caps) => {
+            let level = caps.get(1).unwrap().as_str();
+            let priority = level.trim().parse::<Priority>().ok();
+            (priority, line[caps.get(0).unwrap().end()..].to_string())
+        }
+        None => (None, line.to_string()),
+    }
+}
+
+// Pulls the realtime timestamp, message and priority out of one
+// `journalctl -o json` line. Returns None if the line doesn't carry a
+// usable timestamp (e.g. a malformed or partial line).
+fn parse_journald_json(
+    line: &str,
+    ts_re: &Regex,
+    msg_re: &Regex,
+    prio_re: &Regex,
+) -> Option<(DateTime<FixedOffset>, Option<Priority>, String)> {
+    let micros: i64 = ts_re.captures(line)?.get(1)?.as_str().parse().ok()?;
+    let message = msg_re
+        .captures(line)
+        .and_then(|c| c.get(1))
+        .map(|m| unescape_json(m.as_str()))
+        .unwrap_or_default();
+    let priority = prio_re
+        .captures(line)
+        .and_then(|c| c.get(1))
+        .and_then(|m| m.as_str().parse::<u8>().ok())
+        .and_then(Priority::from_level);
+
+    let offset = *Local::now().offset();
+    let timestamp = Utc
+        .timestamp_opt(micros / 1_000_000, ((micros % 1_000_000) * 1_000) as u32)
+        .single()?
+        .with_timezone(&offset);
+
+    Some((timestamp, priority, message))
+}
+
+fn unescape_json(s: &str) -> String {
+    s.replace("\\\"", "\"")
+        .replace("\\n", "\n")
+        .replace("\\t", "\t")
+        .replace("\\\\", "\\")
+}
+
 fn collect_syslog(
     rand: &str,
     args: &Opt,
+    filter: Arc<LineFilter>,
+    net: Option<Arc<NetSink>>,
     recv: Receiver<()>,
 ) -> Result<thread::JoinHandle<Result<()>>> {
     let mut f_sys = File::create(get_logfile(SYSLOG_FNAME, rand))?;
-    let ctime_iso = Local::now().format("%+").to_string();
-    let ctime_dt = DateTime::parse_from_rfc3339(&ctime_iso).unwrap();
-    let full = args.full;
     let console_off = args.console_off;
     let dmesg = args.dmesg;
+    let format = args.format.clone();
 
     let thread = thread::spawn(move || -> Result<()> {
+        let mut file_encoder = encode::CaptureEncoder;
+        let mut console_encoder = encode::encoder_for(&format)?;
+        let dmesg_decode_re = Regex::new(r"^\w+\s*:\s*(\w+)\s*:\s*").unwrap();
+        let ts_re = Regex::new(r#""__REALTIME_TIMESTAMP"\s*:\s*"(\d+)""#).unwrap();
+        let msg_re = Regex::new(r#""MESSAGE"\s*:\s*"((?:[^"\\]|\\.)*)""#).unwrap();
+        let prio_re = Regex::new(r#""PRIORITY"\s*:\s*"(\d)""#).unwrap();
+
         let mut logger = if dmesg {
             Command::new("dmesg")
                 .arg("--time-format")
                 .arg("iso")
                 .arg("-w")
+                .arg("-x")
                 .stdout(Stdio::piped())
                 .spawn()
                 .expect("Failed to execute dmesg")
@@ -95,7 +168,7 @@ fn collect_syslog(
                 .arg("-k")
                 .arg("-f")
                 .arg("-o")
-                .arg("short-iso-precise")
+                .arg("json")
                 .stdout(Stdio::piped())
                 .spawn()
                 .expect("Failed to execute journalctl")
@@ -110,14 +183,31 @@ fn collect_syslog(
         loop {
             match rx.try_recv() {
                 Ok(line) => {
-                    if let Ok(date) = get_line_split(&Some(Ok(line.clone()))) {
-                        if full || date.0 >= ctime_dt {
-                            let date_iso = date.0.format(TIME_FORMAT).to_string();
-                            writeln!(f_sys, "{} {}", &date_iso, date.1)?;
+                    let parsed = if dmesg {
+                        let (priority, rest) = strip_dmesg_decode_prefix(&line, &dmesg_decode_re);
+                        get_line_split(&Some(Ok(rest)))
+                            .ok()
+                            .map(|(date, msg)| (date, priority, msg))
+                    } else {
+                        parse_journald_json(&line, &ts_re, &msg_re, &prio_re)
+                    };
+
+                    if let Some((date, priority, msg)) = parsed {
+                        if filter.keep(date, &msg, priority, true) {
+                            let log_line = LogLine {
+                                timestamp: date,
+                                source: Source::Syslog,
+                                message: msg,
+                                priority,
+                            };
+                            file_encoder.write_line(&mut f_sys, &log_line)?;
                             if !console_off {
-                                println!("{} {}", &date_iso, date.1);
+                                console_encoder.write_line(&mut io::stdout(), &log_line)?;
                                 io::stdout().flush().unwrap();
                             }
+                            if let Some(sink) = &net {
+                                sink.send(console_encoder.as_mut(), &log_line);
+                            }
                         }
                     }
                 }
@@ -162,25 +252,41 @@ fn get_stdin_line() -> Result<Receiver<String>> {
 fn collect_stdin(
     rand: &str,
     args: &Opt,
+    filter: Arc<LineFilter>,
+    net: Option<Arc<NetSink>>,
     recv: Receiver<()>,
 ) -> Result<thread::JoinHandle<Result<()>>> {
     let mut f_in = File::create(get_logfile(STDIN_FNAME, rand))?;
     let console_off = args.console_off;
+    let format = args.format.clone();
     let thread = thread::spawn(move || -> Result<()> {
         let rx = match get_stdin_line() {
             Ok(r) => r,
             Err(_) => return Err(anyhow!("Error generating communication channels")),
         };
+        let mut file_encoder = encode::CaptureEncoder;
+        let mut console_encoder = encode::encoder_for(&format)?;
 
         loop {
             match rx.try_recv() {
                 Ok(line) => {
                     let dt = Local::now();
-                    let _str = format!("{} {}\n", dt.format(TIME_FORMAT).to_string(), line);
-                    write!(f_in, "{}", &_str)?;
-                    if !console_off {
-                        print!("{}", &_str);
-                        io::stdout().flush().unwrap();
+                    let timestamp = dt.with_timezone(dt.offset());
+                    if filter.keep(timestamp, &line, None, true) {
+                        let log_line = LogLine {
+                            timestamp,
+                            source: Source::Stdin,
+                            message: line,
+                            priority: None,
+                        };
+                        file_encoder.write_line(&mut f_in, &log_line)?;
+                        if !console_off {
+                            console_encoder.write_line(&mut io::stdout(), &log_line)?;
+                            io::stdout().flush().unwrap();
+                        }
+                        if let Some(sink) = &net {
+                            sink.send(console_encoder.as_mut(), &log_line);
+                        }
                     }
                 }
                 Err(_) => {}
@@ -196,7 +302,12 @@ fn collect_stdin(
     return Ok(thread);
 }
 
-fn collect_logs(rand: &str, args: &Opt) -> Result<()> {
+fn collect_logs(
+    rand: &str,
+    args: &Opt,
+    filter: Arc<LineFilter>,
+    net: Option<Arc<NetSink>>,
+) -> Result<()> {
     let receivers;
     let th_stdin;
     let th_syslog;
@@ -206,12 +317,18 @@ fn collect_logs(rand: &str, args: &Opt) -> Result<()> {
         Err(_) => return Err(anyhow!("Error generating communication channels")),
     };
 
-    match collect_syslog(&rand, &args, receivers.0) {
+    match collect_syslog(
+        &rand,
+        &args,
+        Arc::clone(&filter),
+        net.clone(),
+        receivers.0,
+    ) {
         Ok(t) => th_syslog = t,
         Err(_) => return Err(anyhow!("Error generating thread")),
     }
 
-    match collect_stdin(&rand, &args, receivers.1) {
+    match collect_stdin(&rand, &args, Arc::clone(&filter), net.clone(), receivers.1) {
         Ok(t) => th_stdin = t,
         Err(_) => return Err(anyhow!("Error generating thread")),
     }
@@ -268,92 +385,198 @@ fn get_line_split(
     }
 }
 
-fn dump_bufreader(
-    f_out: &mut File,
-    mut reader_lines: io::Lines<io::BufReader<File>>,
-    mut curr_line: Option<Result<String, std::io::Error>>,
-) -> Result<()> {
-    loop {
-        if let Ok(_line) = get_line(&curr_line) {
-            writeln!(f_out, "{}", _line)?;
-        } else {
-            break;
+// Advances `reader` to its next parseable line that also passes `filter`,
+// skipping (rather than stopping on) lines that fail timestamp parsing.
+// Parse failures are tallied on `stats` as we go, since the caller never
+// sees them; filtered-out lines are simply not counted as failures.
+//
+// `own_capture` must only be true for sources dmerg wrote itself through
+// `CaptureEncoder` (the syslog and stdin capture files). Those are the only
+// lines that carry our `<N>`/`<unknown>` priority tag -- stripping it
+// from an arbitrary `--input` file would misread any message that merely
+// starts with a bracketed value (e.g. raw `<3>kernel: oops` dmesg lines)
+// as our own tag and corrupt it -- and the only ones `LineFilter`'s
+// `min_time` cutoff should apply to, since an `--input` file is already
+// timestamped from before this run even started.
+fn next_entry(
+    reader: &mut io::Lines<io::BufReader<File>>,
+    stats: &mut Stats,
+    filter: &LineFilter,
+    own_capture: bool,
+) -> Option<(DateTime<FixedOffset>, Option<Priority>, String)> {
+    for line in reader {
+        match get_line(&Some(line)) {
+            Ok(raw) if raw.trim().is_empty() => stats.record_skip(),
+            Ok(raw) => match get_line_split(&Some(Ok(raw))) {
+                Ok((date, msg)) => {
+                    let (priority, msg) = if own_capture {
+                        priority::extract(&msg)
+                    } else {
+                        (None, msg)
+                    };
+                    if filter.keep(date, &msg, priority, own_capture) {
+                        return Some((date, priority, msg));
+                    }
+                }
+                Err(_) => stats.record_skip(),
+            },
+            Err(_) => stats.record_skip(),
         }
-        curr_line = reader_lines.next();
     }
-    return Ok(());
+    None
 }
 
-fn merge_logs(rand: &str, args: &Opt) -> Result<()> {
+// (timestamp, source index, priority, message) for one merge-heap entry.
+type MergeEntry = (DateTime<FixedOffset>, usize, Option<Priority>, String);
+
+// Maps a merge source index back to the provenance recorded on `LogLine`:
+// 0 is the live syslog capture, 1 the live stdin capture, and anything past
+// that is the (idx - 2)'th `--input` file, in the order given.
+fn source_for(idx: usize) -> Source {
+    match idx {
+        0 => Source::Syslog,
+        1 => Source::Stdin,
+        n => Source::Input(n - 2),
+    }
+}
+
+fn merge_logs(rand: &str, args: &Opt, filter: &LineFilter) -> Result<()> {
     let output_file: String;
     match &args.output {
         Some(v) => output_file = v.to_string(),
         None => output_file = get_logfile(FUSED_FNAME, rand),
     }
 
-    let mut _line_stdin: Option<Result<String, std::io::Error>> = None;
-    let mut _line_syslog: Option<Result<String, std::io::Error>> = None;
+    let mut sources = vec![
+        get_logfile(SYSLOG_FNAME, rand),
+        get_logfile(STDIN_FNAME, rand),
+    ];
+    sources.extend(args.input.iter().cloned());
 
-    let mut stdin_date = DateTime::parse_from_rfc3339(&UNIXTIME).unwrap();
-    let mut syslog_date = DateTime::parse_from_rfc3339(&UNIXTIME).unwrap();
+    let mut readers: Vec<io::Lines<io::BufReader<File>>> = sources
+        .iter()
+        .map(|f| read_lines(f))
+        .collect::<Result<Vec<_>>>()?;
 
-    let mut syslog_lines = read_lines(&get_logfile(SYSLOG_FNAME, rand))?;
-    let mut stdin_lines = read_lines(&get_logfile(STDIN_FNAME, rand))?;
     let mut f_out = File::create(output_file)?;
-
-    let mut end_stdin = false;
-    let mut end_syslog = false;
-    let mut next_stdin = true;
-    let mut next_syslog = true;
-
-    loop {
-        if next_stdin {
-            _line_stdin = stdin_lines.next();
-            match get_line_split(&_line_stdin) {
-                Ok(v) => {
-                    stdin_date = v.0;
-                    next_stdin = false;
-                }
-                Err(_) => {
-                    end_stdin = true;
-                }
-            }
+    let mut encoder = encode::encoder_for(&args.format)?;
+    let mut stats = Stats::new(readers.len());
+
+    let mut heap: BinaryHeap<Reverse<MergeEntry>> = BinaryHeap::new();
+    for (idx, reader) in readers.iter_mut().enumerate() {
+        if let Some((date, priority, msg)) = next_entry(reader, &mut stats, filter, idx < 2) {
+            stats.record_line(idx, date);
+            heap.push(Reverse((date, idx, priority, msg)));
         }
+    }
 
-        if next_syslog {
-            _line_syslog = syslog_lines.next();
-            match get_line_split(&_line_syslog) {
-                Ok(v) => {
-                    syslog_date = v.0;
-                    next_syslog = false;
-                }
-                Err(_) => {
-                    end_syslog = true;
-                }
-            }
+    while let Some(Reverse((date, idx, priority, msg))) = heap.pop() {
+        let log_line = LogLine {
+            timestamp: date,
+            source: source_for(idx),
+            message: msg,
+            priority,
+        };
+        encoder.write_line(&mut f_out, &log_line)?;
+        if let Some((date, priority, msg)) = next_entry(&mut readers[idx], &mut stats, filter, idx < 2) {
+            stats.record_line(idx, date);
+            heap.push(Reverse((date, idx, priority, msg)));
         }
+    }
 
-        // We exit the loop here to be sure each file is read at least once
-        if end_stdin || end_syslog {
-            break;
-        }
+    if args.stats {
+        let source_names: Vec<String> = (0..sources.len()).map(|i| source_for(i).to_string()).collect();
+        stats.write_summary(&mut io::stderr(), &source_names)?;
+    }
 
-        if syslog_date < stdin_date {
-            writeln!(f_out, "{}", get_line(&_line_syslog)?)?;
-            next_syslog = true;
-        } else {
-            writeln!(f_out, "{}", get_line(&_line_stdin)?)?;
-            next_stdin = true;
-        }
+    Ok(())
+}
+
+#[cfg(test)]
+mod merge_tests {
+    use super::*;
+
+    fn parse(s: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_str(s, TIME_FORMAT).unwrap()
     }
 
-    if end_stdin {
-        dump_bufreader(&mut f_out, syslog_lines, _line_syslog)?;
-    } else if end_syslog {
-        dump_bufreader(&mut f_out, stdin_lines, _line_stdin)?;
+    #[test]
+    fn source_for_maps_indices_to_provenance() {
+        assert!(matches!(source_for(0), Source::Syslog));
+        assert!(matches!(source_for(1), Source::Stdin));
+        assert!(matches!(source_for(2), Source::Input(0)));
+        assert!(matches!(source_for(5), Source::Input(3)));
     }
 
-    Ok(())
+    #[test]
+    fn heap_tie_breaks_by_timestamp_then_source_index() {
+        let mut heap: BinaryHeap<Reverse<MergeEntry>> = BinaryHeap::new();
+        // Same timestamp, different sources: lower index wins.
+        heap.push(Reverse((
+            parse("2021-01-01T00:00:01.000000+0000"),
+            1,
+            None,
+            "b".to_string(),
+        )));
+        heap.push(Reverse((
+            parse("2021-01-01T00:00:01.000000+0000"),
+            0,
+            None,
+            "a".to_string(),
+        )));
+        // An earlier timestamp from a higher-index source still comes first.
+        heap.push(Reverse((
+            parse("2021-01-01T00:00:00.000000+0000"),
+            2,
+            None,
+            "c".to_string(),
+        )));
+
+        let order: Vec<usize> =
+            std::iter::from_fn(|| heap.pop().map(|Reverse((_, idx, _, _))| idx)).collect();
+        assert_eq!(order, vec![2, 0, 1]);
+    }
+
+    fn write_temp_file(name: &str, lines: &[&str]) -> String {
+        let path = format!("{}/dmerg_test_{}", std::env::temp_dir().display(), name);
+        let mut f = File::create(&path).unwrap();
+        for line in lines {
+            writeln!(f, "{}", line).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn next_entry_skips_unparseable_lines_and_applies_the_filter() {
+        let path = write_temp_file(
+            "next_entry",
+            &[
+                "not a valid line at all",
+                "2021-01-01T00:00:00.000000+0000 <3> kernel: oops",
+                "2021-01-01T00:00:01.000000+0000 <6> kernel: fine",
+            ],
+        );
+        let mut reader = read_lines(&path).unwrap();
+        let filter =
+            LineFilter::new(&None, &None, &None, &None, &Some("err".to_string()), None).unwrap();
+        let mut stats = Stats::new(1);
+
+        let first = next_entry(&mut reader, &mut stats, &filter, true);
+        assert_eq!(
+            first,
+            Some((
+                parse("2021-01-01T00:00:00.000000+0000"),
+                Some(Priority::Err),
+                "kernel: oops".to_string()
+            ))
+        );
+        // The remaining line is below --min-priority err, so it's filtered
+        // (not a parse failure) and next_entry runs out of lines.
+        let second = next_entry(&mut reader, &mut stats, &filter, true);
+        assert_eq!(second, None);
+
+        fs::remove_file(&path).unwrap();
+    }
 }
 
 fn remove_tmp_files(rand: &str) -> Result<()> {
@@ -392,6 +615,35 @@ struct Opt {
     /// Use dmesg instead of journald
     #[structopt(short, long)]
     dmesg: bool,
+    /// Merge in an already-captured, pre-timestamped log file. Repeatable.
+    #[structopt(long)]
+    input: Vec<String>,
+    /// Output format: text or json.
+    #[structopt(long, default_value = "text")]
+    format: String,
+    /// Print a summary of the merge (counts, time span, peak rate) alongside the merged file.
+    #[structopt(long)]
+    stats: bool,
+    /// Only keep lines whose message matches this regex.
+    #[structopt(long)]
+    grep: Option<String>,
+    /// Drop lines whose message matches this regex.
+    #[structopt(long = "grep-invert")]
+    grep_invert: Option<String>,
+    /// Only keep lines at or after this RFC 3339 timestamp.
+    #[structopt(long)]
+    since: Option<String>,
+    /// Only keep lines at or before this RFC 3339 timestamp.
+    #[structopt(long)]
+    until: Option<String>,
+    /// Drop syslog/kernel lines less severe than this level (0-7 or
+    /// emerg..debug). Lines with no known priority are always kept.
+    #[structopt(long = "min-priority")]
+    min_priority: Option<String>,
+    /// Serve the captured stream live to a collector that connects to this
+    /// <addr:port>, in addition to writing the local file.
+    #[structopt(long)]
+    listen: Option<String>,
 }
 
 fn main() -> Result<()> {
@@ -401,13 +653,30 @@ fn main() -> Result<()> {
         .take(16)
         .map(char::from)
         .collect();
+    // Unless --full is given, drop kernel/syslog lines from before dmerg
+    // started, so a live capture doesn't replay the whole ring buffer.
+    let ctime_iso = Local::now().format("%+").to_string();
+    let ctime_dt = DateTime::parse_from_rfc3339(&ctime_iso).unwrap();
+    let min_time = if args.full { None } else { Some(ctime_dt) };
+    let filter = Arc::new(LineFilter::new(
+        &args.grep,
+        &args.grep_invert,
+        &args.since,
+        &args.until,
+        &args.min_priority,
+        min_time,
+    )?);
+    let net = match &args.listen {
+        Some(addr) => Some(NetSink::listen(addr)?),
+        None => None,
+    };
 
-    if let Err(e) = collect_logs(&rand, &args) {
+    if let Err(e) = collect_logs(&rand, &args, Arc::clone(&filter), net.clone()) {
         remove_tmp_files(&rand)?;
         return Err(anyhow!(e));
     };
 
-    if let Err(e) = merge_logs(&rand, &args) {
+    if let Err(e) = merge_logs(&rand, &args, &filter) {
         remove_tmp_files(&rand)?;
         return Err(anyhow!(e));
     }
@@ -0,0 +1,145 @@
+// SPDX-License-Identifier: GPL-2.0-only
+/*
+ * Copyright (C) 2021 Carles Pey <cpey@pm.me>
+ */
+
+use anyhow::Result;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::encode::{Encoder, LogLine};
+
+/// A live, best-effort sink for the captured stream: binds `addr` and
+/// accepts a single collector connection in the background, then frames
+/// each `LogLine` the way artiq's `Header::write_to` frames its payloads
+/// -- a 4-byte big-endian length prefix followed by the encoded bytes.
+pub struct NetSink {
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl NetSink {
+    /// Binds `addr` right away (so a bad address fails fast) and accepts
+    /// the collector connection on a background thread, so capture can
+    /// start immediately instead of blocking until someone connects --
+    /// the whole point of `--listen` is to catch a device that's about to
+    /// panic, connected collector or not.
+    pub fn listen(addr: &str) -> Result<Arc<Self>> {
+        let listener = TcpListener::bind(addr)?;
+        let sink = Arc::new(NetSink {
+            stream: Mutex::new(None),
+        });
+
+        let addr = addr.to_string();
+        let sink_bg = Arc::clone(&sink);
+        thread::spawn(move || {
+            eprintln!("+ Waiting for a collector to connect on {}", addr);
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    eprintln!("+ Streaming to {}", peer);
+                    *sink_bg.stream.lock().unwrap() = Some(stream);
+                }
+                Err(_) => {}
+            }
+        });
+
+        Ok(sink)
+    }
+
+    /// Encodes and sends `line`. Write failures (e.g. the collector
+    /// disconnected) drop the connection silently instead of propagating,
+    /// so the caller keeps capturing to its local file regardless.
+    pub fn send(&self, encoder: &mut dyn Encoder, line: &LogLine) {
+        let mut guard = self.stream.lock().unwrap();
+        let stream = match guard.as_mut() {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let mut payload = Vec::new();
+        if encoder.write_line(&mut payload, line).is_err() {
+            return;
+        }
+
+        let len = (payload.len() as u32).to_be_bytes();
+        let sent = stream
+            .write_all(&len)
+            .and_then(|()| stream.write_all(&payload));
+        if sent.is_err() {
+            *guard = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::Source;
+    use chrono::DateTime;
+    use std::io::Read;
+
+    struct FakeEncoder;
+
+    impl Encoder for FakeEncoder {
+        fn write_line(&mut self, w: &mut dyn Write, _line: &LogLine) -> Result<()> {
+            w.write_all(b"hi")?;
+            Ok(())
+        }
+    }
+
+    fn line() -> LogLine {
+        LogLine {
+            timestamp: DateTime::parse_from_rfc3339("2021-01-01T00:00:00+00:00").unwrap(),
+            source: Source::Stdin,
+            message: "x".to_string(),
+            priority: None,
+        }
+    }
+
+    // A real loopback connection, not a mock object, since TcpStream's
+    // write half can't be faked behind `dyn Write` without also touching
+    // `send`'s lock-and-replace logic.
+    fn loopback() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn send_frames_the_payload_with_a_length_prefix() {
+        let (client, mut server) = loopback();
+        let sink = NetSink {
+            stream: Mutex::new(Some(client)),
+        };
+
+        sink.send(&mut FakeEncoder, &line());
+
+        let mut buf = [0u8; 6];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..4], &2u32.to_be_bytes());
+        assert_eq!(&buf[4..], b"hi");
+    }
+
+    #[test]
+    fn send_drops_the_connection_on_write_failure() {
+        let (client, server) = loopback();
+        drop(server); // closes the far end so a later write fails
+        let sink = NetSink {
+            stream: Mutex::new(Some(client)),
+        };
+
+        // A write to a just-closed socket doesn't always fail on the very
+        // first call (the peer's FIN/RST needs a round trip to arrive), so
+        // retry until `send` notices and clears the stream.
+        for _ in 0..10 {
+            if sink.stream.lock().unwrap().is_none() {
+                break;
+            }
+            sink.send(&mut FakeEncoder, &line());
+        }
+
+        assert!(sink.stream.lock().unwrap().is_none());
+    }
+}
@@ -0,0 +1,142 @@
+// SPDX-License-Identifier: GPL-2.0-only
+/*
+ * Copyright (C) 2021 Carles Pey <cpey@pm.me>
+ */
+
+use anyhow::{anyhow, Error};
+use std::fmt;
+use std::str::FromStr;
+
+/// Syslog/kernel severity level, the same EMERG(0)..DEBUG(7) mapping
+/// crosvm's syslog code uses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Emerg = 0,
+    Alert = 1,
+    Crit = 2,
+    Err = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+impl Priority {
+    pub fn from_level(level: u8) -> Option<Priority> {
+        match level {
+            0 => Some(Priority::Emerg),
+            1 => Some(Priority::Alert),
+            2 => Some(Priority::Crit),
+            3 => Some(Priority::Err),
+            4 => Some(Priority::Warning),
+            5 => Some(Priority::Notice),
+            6 => Some(Priority::Info),
+            7 => Some(Priority::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn level(self) -> u8 {
+        self as u8
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Priority::Emerg => "emerg",
+            Priority::Alert => "alert",
+            Priority::Crit => "crit",
+            Priority::Err => "err",
+            Priority::Warning => "warning",
+            Priority::Notice => "notice",
+            Priority::Info => "info",
+            Priority::Debug => "debug",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Priority {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, <Priority as FromStr>::Err> {
+        match s.to_lowercase().as_str() {
+            "emerg" | "0" => Ok(Priority::Emerg),
+            "alert" | "1" => Ok(Priority::Alert),
+            "crit" | "2" => Ok(Priority::Crit),
+            "err" | "error" | "3" => Ok(Priority::Err),
+            "warning" | "warn" | "4" => Ok(Priority::Warning),
+            "notice" | "5" => Ok(Priority::Notice),
+            "info" | "6" => Ok(Priority::Info),
+            "debug" | "7" => Ok(Priority::Debug),
+            other => Err(anyhow!("Unknown priority level: {}", other)),
+        }
+    }
+}
+
+/// Strips a leading `<N>`/`<unknown>` priority tag, as written by our own
+/// capture files, off the front of `message`. Messages without a tag (e.g.
+/// from a plain `--input` file) are returned unchanged with `None`.
+pub fn extract(message: &str) -> (Option<Priority>, String) {
+    if let Some(rest) = message.strip_prefix('<') {
+        if let Some(end) = rest.find('>') {
+            let tag = &rest[..end];
+            let remainder = rest[end + 1..].trim_start().to_string();
+            if tag == "unknown" {
+                return (None, remainder);
+            }
+            if let Some(p) = tag.parse::<u8>().ok().and_then(Priority::from_level) {
+                return (Some(p), remainder);
+            }
+        }
+    }
+    (None, message.to_string())
+}
+
+/// The `<N>`/`<unknown>` tag our capture files prefix onto each message.
+pub fn tag(priority: Option<Priority>) -> String {
+    match priority {
+        Some(p) => format!("<{}>", p.level()),
+        None => "<unknown>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_str_accepts_names_and_numbers() {
+        assert_eq!("err".parse::<Priority>().unwrap(), Priority::Err);
+        assert_eq!("ERROR".parse::<Priority>().unwrap(), Priority::Err);
+        assert_eq!("3".parse::<Priority>().unwrap(), Priority::Err);
+        assert!("bogus".parse::<Priority>().is_err());
+    }
+
+    #[test]
+    fn extract_strips_our_own_tag() {
+        assert_eq!(
+            extract("<3> kernel: oops"),
+            (Some(Priority::Err), "kernel: oops".to_string())
+        );
+        assert_eq!(extract("<unknown> hello"), (None, "hello".to_string()));
+    }
+
+    #[test]
+    fn extract_leaves_untagged_messages_alone() {
+        assert_eq!(
+            extract("kernel: oops"),
+            (None, "kernel: oops".to_string())
+        );
+    }
+
+    #[test]
+    fn tag_round_trips_through_extract() {
+        for p in [Priority::Emerg, Priority::Err, Priority::Debug] {
+            let tagged = format!("{} hello", tag(Some(p)));
+            assert_eq!(extract(&tagged), (Some(p), "hello".to_string()));
+        }
+        assert_eq!(extract(&format!("{} hi", tag(None))), (None, "hi".to_string()));
+    }
+}
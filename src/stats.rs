@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: GPL-2.0-only
+/*
+ * Copyright (C) 2021 Carles Pey <cpey@pm.me>
+ */
+
+use anyhow::Result;
+use chrono::{DateTime, FixedOffset};
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Duration;
+
+use crate::TIME_FORMAT;
+
+/// Accumulates summary counters over a merge pass, for `--stats` mode.
+/// Fed one line at a time from `merge_logs`'s k-way merge loop, so it never
+/// needs a second read of the sources.
+pub struct Stats {
+    per_source: Vec<u64>,
+    first: Option<DateTime<FixedOffset>>,
+    last: Option<DateTime<FixedOffset>>,
+    parse_failures: u64,
+    per_second: HashMap<i64, u64>,
+}
+
+impl Stats {
+    pub fn new(n_sources: usize) -> Self {
+        Stats {
+            per_source: vec![0; n_sources],
+            first: None,
+            last: None,
+            parse_failures: 0,
+            per_second: HashMap::new(),
+        }
+    }
+
+    /// Records a line from `source` that was merged in at `timestamp`.
+    pub fn record_line(&mut self, source: usize, timestamp: DateTime<FixedOffset>) {
+        self.per_source[source] += 1;
+        if self.first.map_or(true, |t| timestamp < t) {
+            self.first = Some(timestamp);
+        }
+        if self.last.map_or(true, |t| timestamp > t) {
+            self.last = Some(timestamp);
+        }
+        *self.per_second.entry(timestamp.timestamp()).or_insert(0) += 1;
+    }
+
+    /// Records a line that was skipped because it failed timestamp parsing.
+    pub fn record_skip(&mut self) {
+        self.parse_failures += 1;
+    }
+
+    fn total(&self) -> u64 {
+        self.per_source.iter().sum()
+    }
+
+    fn peak_per_second(&self) -> u64 {
+        self.per_second.values().copied().max().unwrap_or(0)
+    }
+
+    fn span(&self) -> Option<Duration> {
+        match (self.first, self.last) {
+            (Some(f), Some(l)) => (l - f).to_std().ok(),
+            _ => None,
+        }
+    }
+
+    /// Writes the human-readable summary for `--stats` mode.
+    pub fn write_summary(&self, w: &mut dyn Write, source_names: &[String]) -> Result<()> {
+        writeln!(w, "Total lines: {}", self.total())?;
+        for (idx, count) in self.per_source.iter().enumerate() {
+            let name = source_names
+                .get(idx)
+                .map(String::as_str)
+                .unwrap_or("unknown");
+            writeln!(w, "  {}: {}", name, count)?;
+        }
+        match (self.first, self.last) {
+            (Some(f), Some(l)) => {
+                writeln!(
+                    w,
+                    "Time span: {} -> {}",
+                    f.format(TIME_FORMAT),
+                    l.format(TIME_FORMAT)
+                )?;
+                match self.span() {
+                    Some(d) => writeln!(w, "Duration: {:.3}s", d.as_secs_f64())?,
+                    None => writeln!(w, "Duration: n/a")?,
+                }
+            }
+            _ => writeln!(w, "Time span: n/a")?,
+        }
+        writeln!(w, "Peak lines/sec: {}", self.peak_per_second())?;
+        writeln!(w, "Unparseable lines skipped: {}", self.parse_failures)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(s: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(s).unwrap()
+    }
+
+    #[test]
+    fn span_is_zero_for_a_single_line_and_none_before_any() {
+        let mut stats = Stats::new(1);
+        assert_eq!(stats.span(), None);
+        stats.record_line(0, t("2021-01-01T00:00:00+00:00"));
+        assert_eq!(stats.span(), Some(Duration::ZERO));
+        stats.record_line(0, t("2021-01-01T00:00:02+00:00"));
+        assert_eq!(stats.span(), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn peak_per_second_counts_the_busiest_second() {
+        let mut stats = Stats::new(1);
+        stats.record_line(0, t("2021-01-01T00:00:00+00:00"));
+        stats.record_line(0, t("2021-01-01T00:00:00.500000+00:00"));
+        stats.record_line(0, t("2021-01-01T00:00:01+00:00"));
+        assert_eq!(stats.peak_per_second(), 2);
+    }
+
+    #[test]
+    fn total_sums_per_source_counts() {
+        let mut stats = Stats::new(2);
+        stats.record_line(0, t("2021-01-01T00:00:00+00:00"));
+        stats.record_line(1, t("2021-01-01T00:00:01+00:00"));
+        stats.record_line(1, t("2021-01-01T00:00:02+00:00"));
+        assert_eq!(stats.total(), 3);
+        stats.record_skip();
+        assert_eq!(stats.parse_failures, 1);
+    }
+}